@@ -1,12 +1,40 @@
 #![feature(box_syntax)]
 
-use std::{collections::HashMap, error::Error};
-
-use prometheus::{labels, opts, GaugeVec, Registry, Result as PResult, TextEncoder};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{labels, opts, GaugeVec, Registry, TextEncoder};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use thiserror::Error as ThisError;
 use url::Url;
 
+/// Errors produced while talking to the gateway and exporting metrics.
+///
+/// Callers distinguish a transient websocket drop (worth reconnecting) from a fatal gateway/auth failure by matching on
+/// the variant.
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("failed to connect to the deCONZ gateway")]
+    GatewayConnect(#[from] reqwest::Error),
+
+    #[error("failed to connect to the websocket")]
+    WebsocketConnect(#[from] tungstenite::Error),
+
+    #[error("failed to read a websocket event")]
+    EventParse(#[source] tungstenite::Error),
+
+    #[error("failed to parse JSON")]
+    JsonParse(#[from] serde_json::Error),
+
+    #[error("failed to register a prometheus metric")]
+    MetricRegister(#[from] prometheus::Error),
+}
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -16,6 +44,11 @@ use log::{debug, info, warn};
 #[cfg(test)]
 use std::{println as debug, println as warn, println as info};
 
+/// Unix timestamp (seconds) of the last websocket message the stream read, or 0 if none yet.
+///
+/// Updated from `connect` and read back by the `/health` endpoint to produce a liveness signal.
+static LAST_EVENT: AtomicU64 = AtomicU64::new(0);
+
 lazy_static! {
     /// Global prometheus registry for all metrics
     static ref REGISTRY: Registry = Registry::new_custom(Some("deconz".into()), None)
@@ -27,6 +60,12 @@ lazy_static! {
     static ref BATTERY: GaugeVec = GaugeVec::new(opts!("battery", "Battery level in percentage"),
         &["manufacturername", "modelid", "name", "swversion"]).unwrap();
 
+    static ref REACHABLE: GaugeVec = GaugeVec::new(opts!("sensor_reachable", "Whether the sensor is reachable, 1 or 0"),
+        &["manufacturername", "modelid", "name", "swversion"]).unwrap();
+
+    static ref ON: GaugeVec = GaugeVec::new(opts!("sensor_on", "Whether the sensor is switched on, 1 or 0"),
+        &["manufacturername", "modelid", "name", "swversion"]).unwrap();
+
     static ref TEMPERATURE: GaugeVec = GaugeVec::new(opts!("temperature_celsius", "Temperature in degree Celsius"),
         &["manufacturername", "modelid", "name", "swversion", "type"]).unwrap();
 
@@ -35,6 +74,15 @@ lazy_static! {
 
     static ref HUMIDITY: GaugeVec = GaugeVec::new(opts!("humidity_ratio", "Relative humidity in percentage"),
         &["manufacturername", "modelid", "name", "swversion", "type"]).unwrap();
+
+    static ref LIGHT_ON: GaugeVec = GaugeVec::new(opts!("light_on", "Whether the light is on, 1 or 0"),
+        &["manufacturername", "modelid", "name"]).unwrap();
+
+    static ref LIGHT_BRIGHTNESS: GaugeVec = GaugeVec::new(opts!("light_brightness", "Light brightness, 0 to 255"),
+        &["manufacturername", "modelid", "name"]).unwrap();
+
+    static ref LIGHT_REACHABLE: GaugeVec = GaugeVec::new(opts!("light_reachable", "Whether the light is reachable, 1 or 0"),
+        &["manufacturername", "modelid", "name"]).unwrap();
 }
 
 /// deCONZ gateway config
@@ -87,10 +135,25 @@ pub struct Sensor {
     dummy: String,
 }
 
+/// Light info
+///
+/// The full resource arrives with `added` events; later `changed` events only carry `state` deltas, so the labels are
+/// cached here the same way sensor attrs are.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Light {
+    pub manufacturername: String,
+    pub modelid: String,
+    pub name: String,
+    #[serde(default)]
+    pub state: HashMap<String, Value>,
+}
+
 /// State carried around between events.
 #[derive(Default)]
 pub struct State {
     sensors: HashMap<String, Sensor>,
+    lights: HashMap<String, Light>,
+    groups: HashMap<String, HashMap<String, Value>>,
 }
 
 /// Websocket event from deCONZ for Conbee2
@@ -139,18 +202,18 @@ pub struct Event {
 }
 
 /// Callback function executed for every update event
-type Callback = fn(&mut Event, &mut State) -> Result<(), Box<dyn Error>>;
+type Callback = fn(&mut Event, &mut State) -> Result<(), Error>;
 
 /// Read gateway config from deCONZ REST API
-fn gateway(host: &Url, username: &str) -> Result<Gateway, reqwest::Error> {
+fn gateway(host: &Url, username: &str) -> Result<Gateway, Error> {
     let mut host = host.clone();
     host.set_path(&format!("/api/{}/config", username));
     info!("Connecting to API gateway at {host}");
-    reqwest::blocking::get(host)?.json()
+    Ok(reqwest::blocking::get(host)?.json()?)
 }
 
 /// Discover websocket port from gateway config
-fn websocket(host: &Url, username: &str) -> Result<Url, Box<dyn Error>> {
+fn websocket(host: &Url, username: &str) -> Result<Url, Error> {
     let gw = gateway(host, username)?;
 
     INFO.with(&labels! {"name" =>  gw.name.as_str(), "apiversion" => gw.apiversion.as_str()})
@@ -166,22 +229,55 @@ fn websocket(host: &Url, username: &str) -> Result<Url, Box<dyn Error>> {
 }
 
 /// Run listener for websocket events.
-pub fn run(host: &Url, username: &str) -> Result<(), Box<dyn Error>> {
+pub fn run(host: &Url, username: &str) -> Result<(), Error> {
     let socket = websocket(host, username)?;
     register_metrics()?;
     stream(&socket, &mut State::default(), process)
 }
 
-/// Run a callback for each event received over websocket.
+/// Run a callback for each event received over websocket, reconnecting forever when the socket drops.
 //
 // NOTE: A stream of Events would have been much neater than a callback, but Rust makes that API significantly more
 // painful to implement.  Revisit this later.
-fn stream(url: &Url, state: &mut State, callback: Callback) -> Result<(), Box<dyn Error>> {
+fn stream(url: &Url, state: &mut State, callback: Callback) -> Result<(), Error> {
     info!("🔌 Start listening for websocket events at {url}");
 
+    // The gateway restarts, wifi blips and the socket dies; none of that should take the process down. Reconnect
+    // forever with a fibonacci backoff, reusing `state` so cached sensor attrs survive across reconnects. Anything
+    // that isn't a websocket level failure (a bad gateway config, say) is fatal and bubbles up instead.
+    let mut backoff = Backoff::default();
+    loop {
+        match connect(url, state, callback, &mut backoff) {
+            Err(err @ (Error::WebsocketConnect(_) | Error::EventParse(_))) => {
+                let delay = backoff.delay();
+                warn!("Websocket stream dropped: {:?}, reconnecting in {:?}", err, delay);
+                thread::sleep(delay);
+            }
+            Err(err) => return Err(err),
+            Ok(()) => unreachable!("connect only returns on error"),
+        }
+    }
+}
+
+/// Connect once and dispatch every message to `callback` until the socket errors out.
+///
+/// The backoff is reset as soon as a connection stays up long enough to read a single message, so a flaky link that
+/// recovers doesn't keep ratcheting the delay up.
+fn connect(url: &Url, state: &mut State, callback: Callback, backoff: &mut Backoff) -> Result<(), Error> {
     let (mut socket, _) = tungstenite::client::connect(url)?;
+    let mut healthy = false;
+
     loop {
-        match serde_json::from_str::<Event>(socket.read_message()?.to_text()?) {
+        let message = socket.read_message()?;
+        if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            LAST_EVENT.store(now.as_secs(), Ordering::Relaxed);
+        }
+        if !healthy {
+            backoff.reset();
+            healthy = true;
+        }
+
+        match serde_json::from_str::<Event>(message.to_text().map_err(Error::EventParse)?) {
             Ok(mut event) => {
                 // Failing to process a single event is alright, and this process should just continue. Non recoverable
                 // errors should bubble up so that the whole stream can be reestablished.
@@ -196,13 +292,44 @@ fn stream(url: &Url, state: &mut State, callback: Callback) -> Result<(), Box<dy
     }
 }
 
+/// Fibonacci backoff delay between reconnect attempts, starting at 1s and capped at 60s.
+struct Backoff {
+    prev: u64,
+    curr: u64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { prev: 0, curr: 1 }
+    }
+}
+
+impl Backoff {
+    /// Upper bound on the delay in seconds.
+    const CAP: u64 = 60;
+
+    /// Return the current delay and advance the fibonacci sequence.
+    fn delay(&mut self) -> Duration {
+        let secs = self.curr.min(Self::CAP);
+        let next = (self.prev + self.curr).min(Self::CAP);
+        self.prev = self.curr;
+        self.curr = next;
+        Duration::from_secs(secs)
+    }
+
+    /// Reset the sequence back to the start after a healthy connection.
+    fn reset(&mut self) {
+        *self = Backoff::default();
+    }
+}
+
 /// Process events that can be handled and throw away everything else with a warning log.
 ///
 /// The events structure is a bit messy and not in a good shape. See documentation of `Event` for details.
 ///
 /// Events with `attrs` are used to get human readable labels and stored in a static map for future lookup, when state
 /// updates arrive without these attributes.
-fn process(e: &mut Event, state: &mut State) -> Result<(), Box<dyn Error>> {
+fn process(e: &mut Event, state: &mut State) -> Result<(), Error> {
     debug!("Received event for {}", e.id);
 
     // Sensor attributes contains human friendly names and labels. Store them now for future events with no attributes.
@@ -214,6 +341,33 @@ fn process(e: &mut Event, state: &mut State) -> Result<(), Box<dyn Error>> {
         }
     }
 
+    // Light and group resources. `added` events carry the full resource; `changed` events carry only state deltas,
+    // so the human readable labels are cached from the full resource for later lookup.
+    if e.resource == "lights" {
+        if e.event == "added" && !e.light.is_empty() {
+            let light: Light = serde_json::from_value(Value::Object(e.light.clone().into_iter().collect()))?;
+            debug!("Adding light {}: {}", e.id, light.name);
+            set_light_metrics(&light, &light.state);
+            state.lights.insert(e.id.to_string(), light);
+            return Ok(());
+        }
+        if e.event == "changed" && !e.state.is_empty() {
+            if let Some(light) = state.lights.get(&e.id) {
+                set_light_metrics(light, &e.state);
+            } else {
+                warn!("Ignoring state update for unknown light {}: {:?}", e.id, e)
+            }
+            return Ok(());
+        }
+    }
+
+    // Groups export no metrics; the full resource from `added` events is only cached in `state.groups`.
+    if e.resource == "groups" && e.event == "added" && !e.group.is_empty() {
+        debug!("Caching group attrs for {}", e.id);
+        state.groups.insert(e.id.to_string(), e.group.clone());
+        return Ok(());
+    }
+
     // State often has 2 keys, `lastupdated` and another one that is the actual data. Handle those, ignore the rest
     if e.type_ == "event" && e.event == "changed" && !e.state.is_empty() {
         if let Some(sensor) = state.sensors.get(&e.id) {
@@ -249,6 +403,8 @@ fn process(e: &mut Event, state: &mut State) -> Result<(), Box<dyn Error>> {
             if let Some(s) = state.sensors.get(&e.id) {
                 debug!("Updating metric ID:{}, battery:{}", e.id, config.battery);
                 BATTERY.with(&s.labels(false)).set(config.battery);
+                REACHABLE.with(&s.labels(false)).set(config.reachable.into());
+                ON.with(&s.labels(false)).set(config.on.into());
             } else {
                 warn!("Unknown config change, ignoring it: {:?}", config)
             }
@@ -261,6 +417,32 @@ fn process(e: &mut Event, state: &mut State) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Seconds since the stream last read a websocket message, or `None` if it hasn't read any yet.
+///
+/// Used by the `/health` endpoint to decide whether the listener thread is alive and keeping up.
+pub fn since_last_event() -> Option<u64> {
+    let last = LAST_EVENT.load(Ordering::Relaxed);
+    if last == 0 {
+        return None;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(now.saturating_sub(last))
+}
+
+/// Set the light gauges from a `state` block, skipping attributes that aren't present in the delta.
+fn set_light_metrics(light: &Light, state: &HashMap<String, Value>) {
+    let labels = light.labels();
+    if let Some(on) = state.get("on").and_then(Value::as_bool) {
+        LIGHT_ON.with(&labels).set(on.into());
+    }
+    if let Some(bri) = state.get("bri").and_then(Value::as_f64) {
+        LIGHT_BRIGHTNESS.with(&labels).set(bri);
+    }
+    if let Some(reachable) = state.get("reachable").and_then(Value::as_bool) {
+        LIGHT_REACHABLE.with(&labels).set(reachable.into());
+    }
+}
+
 /// Export prometheus metrics as a string
 pub fn metrics() -> String {
     let encoder = TextEncoder::new();
@@ -269,13 +451,19 @@ pub fn metrics() -> String {
 }
 
 // Register metrics
-fn register_metrics() -> PResult<()> {
+fn register_metrics() -> Result<(), Error> {
     info!("Registering metrics",);
     REGISTRY.register(box INFO.clone())?;
     REGISTRY.register(box BATTERY.clone())?;
+    REGISTRY.register(box REACHABLE.clone())?;
+    REGISTRY.register(box ON.clone())?;
     REGISTRY.register(box TEMPERATURE.clone())?;
     REGISTRY.register(box PRESSURE.clone())?;
-    REGISTRY.register(box HUMIDITY.clone())
+    REGISTRY.register(box HUMIDITY.clone())?;
+    REGISTRY.register(box LIGHT_ON.clone())?;
+    REGISTRY.register(box LIGHT_BRIGHTNESS.clone())?;
+    REGISTRY.register(box LIGHT_REACHABLE.clone())?;
+    Ok(())
 }
 
 impl Sensor {
@@ -299,6 +487,17 @@ impl Sensor {
     }
 }
 
+impl Light {
+    /// Convert light into prometheus labels
+    fn labels(&self) -> HashMap<&str, &str> {
+        labels! {
+            "manufacturername" => self.manufacturername.as_str(),
+            "modelid" => self.modelid.as_str(),
+            "name" => self.name.as_str(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
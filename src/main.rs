@@ -1,29 +1,110 @@
 use clap::Parser;
 use log::info;
-use std::{panic, process, thread};
+use serde::Deserialize;
+use std::{error::Error, panic, process, thread};
 use tiny_http::{Method, Response, Server};
 use url::Url;
 
-use deconz_exporter::{metrics, run};
+use deconz_exporter::{metrics, run, since_last_event};
+
+/// Default port to listen for metrics on.
+const DEFAULT_PORT: u16 = 8000;
+
+/// A stream silent longer than this many seconds is considered unhealthy by `/health`.
+const HEALTH_THRESHOLD: u64 = 300;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     /// deCONZ API server url
     #[clap(long, parse(try_from_str = Url::parse))]
-    url: Url,
+    url: Option<Url>,
 
     /// deCONZ API username
     #[clap(long)]
-    username: String,
+    username: Option<String>,
 
     /// Port to listen for metrics
-    #[clap(short, long, default_value_t = 8000)]
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// Path to a TOML config file; individual flags override its values
+    #[clap(long)]
+    config: Option<String>,
+}
+
+/// Exporter configuration, loaded from a TOML file as an alternative to CLI flags.
+///
+/// Handy when running under systemd, where a static config beats a long command line.
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    #[serde(default)]
+    gateway: GatewayConfig,
+    #[serde(default)]
+    exporter: ExporterConfig,
+}
+
+/// `[gateway]` section: how to reach the deCONZ REST API.
+#[derive(Debug, Default, Deserialize)]
+struct GatewayConfig {
+    #[serde(default, with = "serde_url")]
+    url: Option<Url>,
+    #[serde(default)]
+    username: Option<String>,
+}
+
+/// `[exporter]` section: how this process exposes metrics.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ExporterConfig {
     port: u16,
 }
 
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        ExporterConfig { port: DEFAULT_PORT }
+    }
+}
+
+impl Config {
+    /// Parse a TOML config file, filling in defaults for missing fields.
+    fn load(path: &str) -> Result<Config, Box<dyn Error>> {
+        let raw = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&raw)?)
+    }
+}
+
+/// Deserialize an optional `Url` from a TOML string; `url::Url` has no serde impl of its own.
+mod serde_url {
+    use serde::{Deserialize, Deserializer};
+    use url::Url;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Option<Url>, D::Error> {
+        match Option::<String>::deserialize(de)? {
+            Some(s) => Url::parse(&s).map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
 fn main() {
     let args = Args::parse();
+
+    // CLI flags take precedence over the config file, which takes precedence over built in defaults.
+    let config = match &args.config {
+        Some(path) => Config::load(path).expect("Failed to load config file"),
+        None => Config::default(),
+    };
+    let url = args
+        .url
+        .or(config.gateway.url)
+        .expect("A gateway url is required, pass --url or set it in the config file");
+    let username = args
+        .username
+        .or(config.gateway.username)
+        .expect("A gateway username is required, pass --username or set it in the config file");
+    let port = args.port.unwrap_or(config.exporter.port);
+
     env_logger::builder()
         .filter_level(log::LevelFilter::Debug)
         .init();
@@ -40,15 +121,29 @@ fn main() {
     }));
 
     thread::spawn(move || {
-        run(&args.url, &args.username).unwrap();
+        run(&url, &username).unwrap();
     });
 
-    let server = Server::http(format!("0.0.0.0:{}", args.port)).unwrap();
+    let server = Server::http(format!("0.0.0.0:{}", port)).unwrap();
     for request in server.incoming_requests() {
         match (request.method(), request.url()) {
             (Method::Get, "/metrics") => {
                 let _ = request.respond(Response::from_string(metrics()));
             }
+            (Method::Get, "/health") | (Method::Get, "/healthz") => {
+                let response = match since_last_event() {
+                    Some(age) if age <= HEALTH_THRESHOLD => {
+                        Response::from_string(format!("ok, last event {age}s ago\n"))
+                    }
+                    Some(age) => Response::from_string(format!("stream silent for {age}s\n"))
+                        .with_status_code(503),
+                    None => {
+                        Response::from_string("stream has not received any events yet\n")
+                            .with_status_code(503)
+                    }
+                };
+                let _ = request.respond(response);
+            }
             _ => {
                 let _ = request.respond(Response::from_string("Did you mean GET /metrics?\n"));
             }